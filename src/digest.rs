@@ -0,0 +1,301 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// The hash algorithm of a Digest challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn hash(self, data: &str) -> String {
+        match self {
+            Self::Md5 => hex(&Md5::digest(data)),
+            Self::Sha256 => hex(&Sha256::digest(data)),
+        }
+    }
+}
+
+/// A `Proxy-Authenticate: Digest` challenge, RFC 7616.
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    /// The name of the algorithm, echoed back in the response.
+    algorithm_name: String,
+    session: bool,
+    /// Whether `qop=auth` was offered.
+    qop_auth: bool,
+}
+
+impl Challenge {
+    /// Parses the value of a `Proxy-Authenticate` header.
+    ///
+    /// Returns `None` if it is not a Digest challenge, or if it requires
+    /// something unsupported, like an unknown algorithm or `qop=auth-int`
+    /// only.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (scheme, params) = value.trim().split_once(' ').unwrap_or((value, ""));
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return None;
+        }
+
+        let mut realm = None;
+        let mut nonce = None;
+        let mut opaque = None;
+        let mut algorithm_name = "MD5".to_string();
+        let mut qop = None;
+        for (key, value) in parse_params(params)? {
+            match key.to_ascii_lowercase().as_str() {
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                "opaque" => opaque = Some(value),
+                "algorithm" => algorithm_name = value,
+                "qop" => qop = Some(value),
+                _ => {}
+            }
+        }
+
+        let (algorithm, session) = match algorithm_name.to_ascii_uppercase().as_str() {
+            "MD5" => (Algorithm::Md5, false),
+            "MD5-SESS" => (Algorithm::Md5, true),
+            "SHA-256" => (Algorithm::Sha256, false),
+            "SHA-256-SESS" => (Algorithm::Sha256, true),
+            _ => return None,
+        };
+        let qop_auth = match qop {
+            None => false,
+            Some(qop) => {
+                if !qop
+                    .split(',')
+                    .any(|q| q.trim().eq_ignore_ascii_case("auth"))
+                {
+                    return None;
+                }
+                true
+            }
+        };
+
+        Some(Self {
+            realm: realm?,
+            nonce: nonce?,
+            opaque,
+            algorithm,
+            algorithm_name,
+            session,
+            qop_auth,
+        })
+    }
+
+    /// Picks the strongest supported Digest challenge among the values of the
+    /// `Proxy-Authenticate` headers.
+    pub fn select<'a>(values: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        values
+            .into_iter()
+            .filter_map(Self::parse)
+            .max_by_key(|c| c.algorithm == Algorithm::Sha256)
+    }
+
+    /// Builds the value of the `Proxy-Authorization` header answering this
+    /// challenge for `CONNECT uri`.
+    pub fn authorization(&self, user: &str, password: &str, uri: &str) -> String {
+        self.authorization_with(user, password, uri, &cnonce())
+    }
+
+    /// Like [`Challenge::authorization`], with the client nonce `cnonce`.
+    fn authorization_with(&self, user: &str, password: &str, uri: &str, cnonce: &str) -> String {
+        let hash = |data: &str| self.algorithm.hash(data);
+
+        let nc = "00000001";
+        let mut ha1 = hash(&format!("{}:{}:{}", user, self.realm, password));
+        if self.session {
+            ha1 = hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = hash(&format!("CONNECT:{}", uri));
+        let response = if self.qop_auth {
+            hash(&format!(
+                "{}:{}:{}:{}:auth:{}",
+                ha1, self.nonce, nc, cnonce, ha2
+            ))
+        } else {
+            hash(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        };
+
+        let mut value = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+            quote(user),
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(uri),
+            self.algorithm_name,
+            response
+        );
+        if self.qop_auth {
+            value += &format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce);
+        }
+        if let Some(opaque) = &self.opaque {
+            value += &format!(", opaque=\"{}\"", quote(opaque));
+        }
+        value
+    }
+}
+
+/// Parses comma separated `key=value` pairs, where the value is either a
+/// token or a quoted string.
+fn parse_params(s: &str) -> Option<Vec<(String, String)>> {
+    let mut params = vec![];
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=') {
+            key.push(c);
+        }
+        chars.next()?;
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => value.push(chars.next()?),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                value.push(c);
+            }
+        }
+        params.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    Some(params)
+}
+
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn cnonce() -> String {
+    hex(&random_bytes())
+}
+
+/// Random bytes for values that only need to be unique, like the client
+/// nonce, falling back to the time and the process ID without a system
+/// random source.
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        bytes = (now.as_nanos() ^ ((std::process::id() as u128) << 96)).to_le_bytes();
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The challenge of the RFC 7616 example, with `algorithm`.
+    fn rfc_challenge(algorithm: &str) -> String {
+        format!(
+            "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", \
+             algorithm={}, nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+             opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"",
+            algorithm
+        )
+    }
+
+    #[test]
+    fn challenge_is_parsed() {
+        let challenge = Challenge::parse(&rfc_challenge("SHA-256")).unwrap();
+        assert_eq!(challenge.realm, "http-auth@example.org");
+        assert_eq!(
+            challenge.nonce,
+            "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v"
+        );
+        assert_eq!(
+            challenge.opaque.as_deref(),
+            Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS")
+        );
+        assert_eq!(
+            (challenge.algorithm, challenge.session),
+            (Algorithm::Sha256, false)
+        );
+        assert!(challenge.qop_auth);
+
+        let challenge =
+            Challenge::parse(r#"digest realm="a \"b\"", nonce=n, algorithm=md5-sess"#).unwrap();
+        assert_eq!(challenge.realm, "a \"b\"");
+        assert_eq!(
+            (challenge.algorithm, challenge.session),
+            (Algorithm::Md5, true)
+        );
+        assert!(!challenge.qop_auth);
+
+        for value in [
+            "Basic realm=\"proxy\"",
+            "Digest nonce=\"n\"",
+            "Digest realm=\"r\", nonce=\"n\", qop=\"auth-int\"",
+            "Digest realm=\"r\", nonce=\"n\", algorithm=SHA-512-256",
+            "Digest realm=\"r, nonce=\"n\"",
+        ] {
+            assert!(Challenge::parse(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn strongest_challenge_is_selected() {
+        let md5 = rfc_challenge("MD5");
+        let sha256 = rfc_challenge("SHA-256");
+        let challenge = Challenge::select(["Basic realm=\"proxy\"", &md5, &sha256]).unwrap();
+        assert_eq!(challenge.algorithm, Algorithm::Sha256);
+        assert!(Challenge::select(["Basic realm=\"proxy\""]).is_none());
+    }
+
+    #[test]
+    fn authorization_answers_the_rfc_example() {
+        // The responses of the RFC 7616 example, for a CONNECT request.
+        for (algorithm, response) in [
+            ("MD5", "706d8ad117b409ad9bb4e9a7ffbdb109"),
+            (
+                "SHA-256",
+                "4d49a899ad7293a45c270572331f9461dece0060094c72d68240926445193f9a",
+            ),
+        ] {
+            let challenge = Challenge::parse(&rfc_challenge(algorithm)).unwrap();
+            let value = challenge.authorization_with(
+                "Mufasa",
+                "Circle of Life",
+                "example.com:22",
+                "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+            );
+            assert_eq!(
+                value,
+                format!(
+                    "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", \
+                     nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+                     uri=\"example.com:22\", algorithm={}, response=\"{}\", qop=auth, \
+                     nc=00000001, cnonce=\"f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ\", \
+                     opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"",
+                    algorithm, response
+                )
+            );
+        }
+    }
+}
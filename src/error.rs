@@ -0,0 +1,317 @@
+use std::{fmt, io, time::Duration};
+
+/// Errors that abort a rockscrew session.
+///
+/// Each variant maps to a process exit code, see [`Error::exit_code`].
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid arguments or configuration.
+    Usage(String),
+    /// The auth file could not be read.
+    AuthFile(io::Error),
+    /// The config file could not be read or parsed.
+    Config(String),
+    /// The certificates for the TLS connection to the proxy could not be
+    /// loaded.
+    Certificate(io::Error),
+    /// The host name of `peer`, the proxy or the destination, could not be
+    /// resolved.
+    Resolve {
+        peer: &'static str,
+        host: String,
+        error: io::Error,
+    },
+    /// The connection to the proxy could not be established.
+    Connect(io::Error),
+    /// The direct connection to the destination could not be established.
+    ConnectDirect(io::Error),
+    /// Connecting to the proxy timed out.
+    ConnectTimeout,
+    /// Waiting for the response to the CONNECT request timed out.
+    ResponseTimeout,
+    /// The proxy sent no byte of the response within the time limit.
+    FirstByteTimeout(Duration),
+    /// The TLS handshake with the proxy failed.
+    Tls(io::Error),
+    /// The CONNECT request could not be sent.
+    Send(io::Error),
+    /// The response to the CONNECT request could not be received.
+    Receive(io::Error),
+    /// The response to the CONNECT request is not valid HTTP.
+    Parse(httparse::Error),
+    /// Authenticating with the proxy failed, like when it doesn't offer the
+    /// scheme that must be used.
+    AuthScheme(String),
+    /// The SOCKS5 proxy refused to open the tunnel to `dest` with the reply
+    /// code.
+    Socks { dest: String, reply: u8 },
+    /// The proxy refused to open the tunnel to `dest` with status `code` and
+    /// its reason phrase. `expected` lists the accepted codes, if not any
+    /// `2xx`, and `retry_after` is the delay from a `Retry-After` header.
+    Refused {
+        dest: String,
+        code: u16,
+        reason: String,
+        expected: Vec<u16>,
+        retry_after: Option<Duration>,
+    },
+    /// The local listening socket failed.
+    Listen(io::Error),
+    /// The log file could not be opened.
+    LogFile(io::Error),
+    /// The file for the received data could not be opened.
+    Output(io::Error),
+    /// The connection to the proxy failed while forwarding data.
+    ForwardProxy(io::Error),
+    /// Reading or writing the local side failed while forwarding data.
+    ForwardLocal(io::Error),
+    /// No data was forwarded within the idle timeout.
+    IdleTimeout,
+    /// The session reached its maximum duration.
+    MaxDuration(Duration),
+    /// The process received SIGINT or SIGTERM, with the signal number.
+    Interrupted(i32),
+}
+
+impl Error {
+    /// The exit code of the process when it fails with this error.
+    ///
+    /// * 1: invalid arguments or local configuration.
+    /// * 2: cannot talk to the proxy.
+    /// * 3: the proxy refused the CONNECT request.
+    /// * 4: an I/O error occurred while forwarding data.
+    /// * 5: the tunnel was idle for too long.
+    /// * 6: the proxy was reached but the destination was not, like with a
+    ///   502, 503 or 504 status or a SOCKS5 unreachable or connection refused
+    ///   reply.
+    /// * 7: the session reached its maximum duration.
+    /// * 128 + the signal number: interrupted by a signal.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Usage(_)
+            | Self::AuthFile(_)
+            | Self::Config(_)
+            | Self::Certificate(_)
+            | Self::Listen(_)
+            | Self::LogFile(_)
+            | Self::Output(_) => 1,
+            Self::Resolve { .. }
+            | Self::Connect(_)
+            | Self::ConnectDirect(_)
+            | Self::ConnectTimeout
+            | Self::ResponseTimeout
+            | Self::FirstByteTimeout(_)
+            | Self::Tls(_)
+            | Self::Send(_)
+            | Self::Receive(_) => 2,
+            Self::Refused {
+                code: 502..=504, ..
+            }
+            | Self::Socks { reply: 3..=6, .. } => 6,
+            Self::Parse(_) | Self::AuthScheme(_) | Self::Socks { .. } | Self::Refused { .. } => 3,
+            Self::ForwardProxy(_) | Self::ForwardLocal(_) => 4,
+            Self::IdleTimeout => 5,
+            Self::MaxDuration(_) => 7,
+            Self::Interrupted(signal) => 128 + signal,
+        }
+    }
+
+    /// How long the proxy asked to wait before trying again, with a 503 or
+    /// 504 status and a `Retry-After` header.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Refused {
+                code: 503 | 504,
+                retry_after,
+                ..
+            } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Whether trying again could succeed, like when the proxy cannot be
+    /// reached or returns a 5xx status. Authentication failures and other
+    /// refusals are not transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Resolve { .. }
+            | Self::Connect(_)
+            | Self::ConnectDirect(_)
+            | Self::ConnectTimeout
+            | Self::ResponseTimeout
+            | Self::FirstByteTimeout(_)
+            | Self::Send(_)
+            | Self::Receive(_) => true,
+            Self::Refused { code, .. } => (500..600).contains(code),
+            // Failures to reach the destination, like a 502 or 504.
+            Self::Socks { reply, .. } => matches!(reply, 1 | 3 | 4 | 5 | 6),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage(msg) => f.write_str(msg),
+            Self::AuthFile(e) => write!(f, "cannot read auth file: {}", e),
+            Self::Config(msg) => write!(f, "invalid config file: {}", msg),
+            Self::Certificate(e) => write!(f, "cannot load certificates: {}", e),
+            Self::Resolve { peer, host, error } => {
+                write!(f, "cannot resolve {} host '{}': {}", peer, host, error)
+            }
+            Self::Connect(e) => write!(f, "cannot connect to proxy: {}", e),
+            Self::ConnectDirect(e) => write!(f, "cannot connect to destination: {}", e),
+            Self::ConnectTimeout => f.write_str("timed out connecting to proxy"),
+            Self::ResponseTimeout => f.write_str("timed out waiting for CONNECT response"),
+            Self::FirstByteTimeout(limit) => write!(
+                f,
+                "proxy accepted connection but sent no response within {} seconds",
+                limit.as_secs_f64()
+            ),
+            Self::Tls(e) => write!(f, "TLS handshake with proxy failed: {}", e),
+            Self::Send(e) => write!(f, "cannot send connect request: {}", e),
+            Self::Receive(e) => write!(f, "cannot read connect response: {}", e),
+            Self::Parse(e) => write!(f, "cannot parse connect response: {}", parse_error(*e)),
+            Self::AuthScheme(msg) => write!(f, "cannot authenticate with proxy: {}", msg),
+            Self::Socks { dest, reply } => write!(
+                f,
+                "SOCKS5 proxy rejected CONNECT to {}: {}",
+                dest,
+                crate::socks::reply_text(*reply)
+            ),
+            Self::Refused {
+                dest,
+                code,
+                reason,
+                expected,
+                ..
+            } => {
+                write!(f, "proxy rejected CONNECT to {}: {}", dest, code)?;
+                if !reason.is_empty() {
+                    write!(f, " {}", reason)?;
+                }
+                if !expected.is_empty() {
+                    let expected: Vec<_> = expected.iter().map(u16::to_string).collect();
+                    write!(f, " (expected {})", expected.join(", "))?;
+                }
+                Ok(())
+            }
+            Self::Listen(e) => write!(f, "cannot listen: {}", e),
+            Self::LogFile(e) => write!(f, "cannot open log file: {}", e),
+            Self::Output(e) => write!(f, "cannot open output file: {}", e),
+            Self::ForwardProxy(e) => write!(f, "connection to proxy lost while forwarding: {}", e),
+            Self::ForwardLocal(e) => write!(f, "local side failed while forwarding: {}", e),
+            Self::IdleTimeout => f.write_str("tunnel closed after idle timeout"),
+            Self::MaxDuration(limit) => write!(
+                f,
+                "tunnel closed after the maximum duration of {} seconds",
+                limit.as_secs_f64()
+            ),
+            Self::Interrupted(signal) => write!(f, "interrupted by signal {}", signal),
+        }
+    }
+}
+
+/// What is wrong with the response for each error of httparse, more
+/// specific than its own descriptions.
+fn parse_error(e: httparse::Error) -> &'static str {
+    match e {
+        httparse::Error::Status => "malformed status line",
+        httparse::Error::Version => "malformed status line, invalid HTTP version",
+        httparse::Error::Token => "invalid character where a token is expected",
+        httparse::Error::HeaderName => "invalid byte in a header name",
+        httparse::Error::HeaderValue => "invalid byte in a header value",
+        httparse::Error::NewLine => "malformed line ending",
+        httparse::Error::TooManyHeaders => "more than 1024 headers",
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Usage(_)
+            | Self::Config(_)
+            | Self::AuthScheme(_)
+            | Self::Socks { .. }
+            | Self::ConnectTimeout
+            | Self::ResponseTimeout
+            | Self::FirstByteTimeout(_)
+            | Self::Refused { .. }
+            | Self::IdleTimeout
+            | Self::MaxDuration(_)
+            | Self::Interrupted(_) => None,
+            Self::AuthFile(e)
+            | Self::Certificate(e)
+            | Self::Connect(e)
+            | Self::ConnectDirect(e)
+            | Self::Tls(e)
+            | Self::Send(e)
+            | Self::Receive(e)
+            | Self::Listen(e)
+            | Self::LogFile(e)
+            | Self::Output(e)
+            | Self::ForwardProxy(e)
+            | Self::ForwardLocal(e) => Some(e),
+            Self::Resolve { error, .. } => Some(error),
+            Self::Parse(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refused(code: u16) -> Error {
+        Error::Refused {
+            dest: "example.com:22".into(),
+            code,
+            reason: String::new(),
+            expected: vec![],
+            retry_after: None,
+        }
+    }
+
+    fn socks(reply: u8) -> Error {
+        Error::Socks {
+            dest: "example.com:22".into(),
+            reply,
+        }
+    }
+
+    #[test]
+    fn exit_code_per_category() {
+        let io = || io::Error::other("failed");
+        let cases = [
+            (Error::Usage("bad".into()), 1),
+            (Error::AuthFile(io()), 1),
+            (Error::Listen(io()), 1),
+            (Error::Output(io()), 1),
+            (Error::Connect(io()), 2),
+            (Error::ConnectTimeout, 2),
+            (Error::Receive(io()), 2),
+            (Error::Parse(httparse::Error::Status), 3),
+            (Error::AuthScheme("Digest".into()), 3),
+            (refused(403), 3),
+            (refused(407), 3),
+            (refused(500), 3),
+            (socks(1), 3),
+            (socks(2), 3),
+            (refused(502), 6),
+            (refused(503), 6),
+            (refused(504), 6),
+            (socks(3), 6),
+            (socks(5), 6),
+            (socks(6), 6),
+            (Error::ForwardProxy(io()), 4),
+            (Error::ForwardLocal(io()), 4),
+            (Error::IdleTimeout, 5),
+            (Error::MaxDuration(Duration::from_secs(1)), 7),
+            (Error::Interrupted(15), 143),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{:?}", error);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    panic::resume_unwind,
+    time::Duration,
+};
+
+use compio::{driver::AsRawFd, net::TcpStream, runtime::spawn_blocking};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+
+/// The address family used to connect to the proxy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// IPv4 or IPv6, in the order of the resolved addresses.
+    #[default]
+    Any,
+    /// IPv4 only.
+    Ipv4,
+    /// IPv6 only.
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Ipv4 => addr.is_ipv4(),
+            Self::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Common services, for systems without a services database.
+const SERVICES: &[(&str, u16)] = &[
+    ("ftp", 21),
+    ("ssh", 22),
+    ("telnet", 23),
+    ("smtp", 25),
+    ("domain", 53),
+    ("http", 80),
+    ("pop3", 110),
+    ("imap", 143),
+    ("https", 443),
+    ("submissions", 465),
+    ("submission", 587),
+    ("imaps", 993),
+    ("pop3s", 995),
+    ("socks", 1080),
+    ("mysql", 3306),
+    ("rdp", 3389),
+    ("postgresql", 5432),
+    ("vnc", 5900),
+];
+
+/// Parses a port number, or looks up a TCP service name like `https` in
+/// `/etc/services` and then in a small built-in table.
+pub fn parse_port(port: &str) -> Option<u16> {
+    if let Ok(port) = port.parse() {
+        return Some(port);
+    }
+    #[cfg(unix)]
+    if let Some(port) = std::fs::read_to_string("/etc/services")
+        .ok()
+        .and_then(|services| lookup_service(&services, port))
+    {
+        return Some(port);
+    }
+    SERVICES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(port))
+        .map(|(_, port)| *port)
+}
+
+/// Finds the TCP port of `name` in the contents of a services file, where
+/// each line is `name port/protocol aliases... # comment`.
+#[cfg(unix)]
+fn lookup_service(services: &str, name: &str) -> Option<u16> {
+    services.lines().find_map(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let service = fields.next()?;
+        let port = fields.next()?.strip_suffix("/tcp")?.parse().ok()?;
+        (service == name || fields.any(|alias| alias == name)).then_some(port)
+    })
+}
+
+/// Calls `f` with the socket of `stream`, to get or set socket options that
+/// compio doesn't expose.
+pub(crate) fn with_socket<T>(stream: &TcpStream, f: impl FnOnce(SockRef) -> T) -> T {
+    // SAFETY: the socket is owned by `stream`, which outlives the borrow.
+    #[cfg(unix)]
+    let socket = unsafe { std::os::fd::BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+    #[cfg(windows)]
+    let socket =
+        unsafe { std::os::windows::io::BorrowedSocket::borrow_raw(stream.as_raw_fd() as _) };
+    f(SockRef::from(&socket))
+}
+
+/// Enables TCP keepalive on `stream`, sending the first probe after `time`
+/// without traffic.
+///
+/// The interval and count of the probes keep the system defaults, as they
+/// can't be set everywhere. OpenBSD ignores `time` too, and uses the
+/// system-wide idle time.
+pub(crate) fn set_keepalive(stream: &TcpStream, time: Duration) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(time);
+    with_socket(stream, |s| s.set_tcp_keepalive(&keepalive))
+}
+
+/// Resolves `host:port`, keeping the addresses of `family` in order.
+pub(crate) async fn resolve(
+    host: &str,
+    port: u16,
+    family: AddressFamily,
+) -> io::Result<Vec<SocketAddr>> {
+    let addrs = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => {
+            let host = host.to_string();
+            spawn_blocking(move || {
+                (host.as_str(), port)
+                    .to_socket_addrs()
+                    .map(Iterator::collect::<Vec<_>>)
+            })
+            .await
+            .unwrap_or_else(|e| resume_unwind(e))?
+        }
+    };
+    Ok(addrs
+        .into_iter()
+        .filter(|addr| family.matches(addr))
+        .collect())
+}
+
+/// Connects to `addr` from the local address `bind`.
+///
+/// compio can't bind a socket before connecting it, so this is done on a
+/// blocking thread.
+pub(crate) async fn connect_from(addr: SocketAddr, bind: SocketAddr) -> io::Result<TcpStream> {
+    let stream = spawn_blocking(move || {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.bind(&bind.into())?;
+        socket.connect(&addr.into())?;
+        io::Result::Ok(socket.into())
+    })
+    .await
+    .unwrap_or_else(|e| resume_unwind(e))?;
+    TcpStream::from_std(stream)
+}
@@ -0,0 +1,306 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use compio::{
+    buf::{IntoInner, IoBuf},
+    io::{AsyncReadExt, AsyncWriteExt},
+    BufResult,
+};
+
+use crate::{Auth, Error, ProxyStream, TunnelConfig};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// The meaning of a SOCKS5 reply code, RFC 1928.
+pub(crate) fn reply_text(reply: u8) -> &'static str {
+    match reply {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Asks the SOCKS5 proxy on `stream` to connect to the destination, with
+/// the username and password in `auth` if the proxy wants them, RFC 1929.
+pub(crate) async fn connect(
+    stream: &mut ProxyStream,
+    auth: &Auth,
+    config: &TunnelConfig,
+) -> Result<(), Error> {
+    let credentials = match auth {
+        Auth::None => None,
+        Auth::Basic(credentials) => Some(credentials.split_once(':').unwrap_or((credentials, ""))),
+        _ => {
+            return Err(Error::Usage(
+                "SOCKS5 needs a plain username and password".into(),
+            ))
+        }
+    };
+    let methods = match credentials {
+        Some(_) => vec![VERSION, 2, NO_AUTH, USER_PASSWORD],
+        None => vec![VERSION, 1, NO_AUTH],
+    };
+    send(stream, methods).await?;
+    let reply = read(stream, 2).await?;
+    if reply[0] != VERSION {
+        return Err(invalid(
+            "endpoint did not speak SOCKS5 (not a SOCKS proxy?)",
+        ));
+    }
+    match (reply[1], credentials) {
+        (NO_AUTH, _) => {}
+        (USER_PASSWORD, Some((user, password))) => {
+            verbose!(config, "SOCKS5 authenticating as {}", user);
+            let mut request = vec![1];
+            for field in [user, password] {
+                let len = u8::try_from(field.len()).map_err(|_| {
+                    Error::Usage("SOCKS5 usernames and passwords are limited to 255 bytes".into())
+                })?;
+                request.push(len);
+                request.extend_from_slice(field.as_bytes());
+            }
+            send(stream, request).await?;
+            if read(stream, 2).await?[1] != 0 {
+                return Err(Error::AuthScheme(
+                    "the SOCKS5 proxy rejected the credentials".into(),
+                ));
+            }
+        }
+        (NO_ACCEPTABLE_METHOD | USER_PASSWORD, _) => {
+            return Err(Error::AuthScheme(if credentials.is_some() {
+                "the SOCKS5 proxy accepts no offered method".into()
+            } else {
+                "the SOCKS5 proxy requires credentials".into()
+            }));
+        }
+        _ => return Err(invalid("SOCKS5 proxy chose an unknown method")),
+    }
+
+    let host = config.dest_host.as_str();
+    verbose!(config, "SOCKS5 CONNECT {}:{}", host, config.dest_port);
+    let mut request = vec![VERSION, CONNECT, 0];
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .and_then(|host| host.parse::<Ipv6Addr>().ok().map(IpAddr::V6))
+        .or_else(|| host.parse().ok());
+    match ip {
+        Some(IpAddr::V4(ip)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Some(IpAddr::V6(ip)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        None => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| Error::Usage(format!("invalid destination host '{}'", host)))?;
+            request.extend_from_slice(&[DOMAIN, len]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&config.dest_port.to_be_bytes());
+    send(stream, request).await?;
+
+    let reply = read(stream, 4).await?;
+    if reply[0] != VERSION {
+        return Err(invalid("invalid SOCKS5 reply"));
+    }
+    if reply[1] != 0 {
+        return Err(Error::Socks {
+            dest: format!("{}:{}", host, config.dest_port),
+            reply: reply[1],
+        });
+    }
+    // The bound address and port, which aren't needed.
+    let len = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => read(stream, 1).await?[0] as usize,
+        _ => return Err(invalid("invalid address type in SOCKS5 reply")),
+    };
+    read(stream, len + 2).await?;
+    verbose!(config, "SOCKS5 tunnel established");
+    Ok(())
+}
+
+async fn send(stream: &mut ProxyStream, message: Vec<u8>) -> Result<(), Error> {
+    stream.write_all(message).await.0.map_err(Error::Send)
+}
+
+async fn read(stream: &mut ProxyStream, len: usize) -> Result<Vec<u8>, Error> {
+    let buffer = Vec::with_capacity(len);
+    let BufResult(res, slice) = stream.read_exact(buffer.slice(..len)).await;
+    res.map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Receive(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "proxy closed connection during the SOCKS5 handshake",
+            ))
+        } else {
+            Error::Receive(e)
+        }
+    })?;
+    Ok(slice.into_inner())
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::Receive(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compio::net::{TcpListener, TcpStream};
+
+    /// What the SOCKS5 server stub does.
+    struct Stub {
+        /// The username and password it requires, if any.
+        credentials: Option<(&'static [u8], &'static [u8])>,
+        /// The reply code to the CONNECT request.
+        reply: u8,
+    }
+
+    async fn read_n(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let BufResult(res, data) = stream.read_exact(Vec::with_capacity(len)).await;
+        res.unwrap();
+        data
+    }
+
+    impl Stub {
+        /// Serves one handshake on `stream`, returning the CONNECT request,
+        /// or `None` if it ended before it.
+        async fn serve(&self, mut stream: TcpStream) -> Option<Vec<u8>> {
+            let greeting = read_n(&mut stream, 2).await;
+            let methods = read_n(&mut stream, greeting[1] as usize).await;
+            let method = match self.credentials {
+                None => NO_AUTH,
+                Some(_) if methods.contains(&USER_PASSWORD) => USER_PASSWORD,
+                Some(_) => NO_ACCEPTABLE_METHOD,
+            };
+            stream.write_all(vec![VERSION, method]).await.0.unwrap();
+            match (method, self.credentials) {
+                (NO_ACCEPTABLE_METHOD, _) => return None,
+                (USER_PASSWORD, Some((user, password))) => {
+                    let len = read_n(&mut stream, 2).await[1] as usize;
+                    let got_user = read_n(&mut stream, len).await;
+                    let len = read_n(&mut stream, 1).await[0] as usize;
+                    let got_password = read_n(&mut stream, len).await;
+                    let ok = got_user == user && got_password == password;
+                    stream.write_all(vec![1, !ok as u8]).await.0.unwrap();
+                    if !ok {
+                        return None;
+                    }
+                }
+                _ => {}
+            }
+            let mut request = read_n(&mut stream, 4).await;
+            let len = match request[3] {
+                IPV4 => 4,
+                IPV6 => 16,
+                _ => {
+                    let len = read_n(&mut stream, 1).await;
+                    request.extend(&len);
+                    len[0] as usize
+                }
+            };
+            request.extend(read_n(&mut stream, len + 2).await);
+            let reply = vec![VERSION, self.reply, 0, IPV4, 127, 0, 0, 1, 0, 80];
+            stream.write_all(reply).await.0.unwrap();
+            Some(request)
+        }
+    }
+
+    /// Runs the handshake for `dest_host` with `auth` against `stub`,
+    /// returning its result and the CONNECT request the stub got.
+    async fn handshake(
+        stub: Stub,
+        dest_host: &str,
+        auth: Auth,
+    ) -> (Result<(), Error>, Option<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = TunnelConfig::new("127.0.0.1", addr.port(), dest_host, 22);
+        let client = async {
+            let mut stream = ProxyStream::Tcp(TcpStream::connect(addr).await.unwrap());
+            connect(&mut stream, &auth, &config).await
+        };
+        let server = async { stub.serve(listener.accept().await.unwrap().0).await };
+        futures_util::join!(client, server)
+    }
+
+    #[compio::test]
+    async fn connect_without_auth() {
+        let stub = Stub {
+            credentials: None,
+            reply: 0,
+        };
+        let (res, request) = handshake(stub, "example.com", Auth::None).await;
+        res.unwrap();
+        let mut expected = vec![VERSION, CONNECT, 0, DOMAIN, 11];
+        expected.extend(b"example.com\0\x16");
+        assert_eq!(request.unwrap(), expected);
+    }
+
+    #[compio::test]
+    async fn connect_with_username_and_password() {
+        let stub = Stub {
+            credentials: Some((b"alice", b"se:cret")),
+            reply: 0,
+        };
+        let auth = Auth::Basic("alice:se:cret".into());
+        let (res, request) = handshake(stub, "2001:db8::1", auth).await;
+        res.unwrap();
+        let mut expected = vec![VERSION, CONNECT, 0, IPV6];
+        expected.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend([0, 22]);
+        assert_eq!(request.unwrap(), expected);
+    }
+
+    #[compio::test]
+    async fn rejected_credentials_fail() {
+        let stub = || Stub {
+            credentials: Some((b"alice", b"secret")),
+            reply: 0,
+        };
+        let (res, _) = handshake(stub(), "example.com", Auth::Basic("alice:wrong".into())).await;
+        let Err(Error::AuthScheme(msg)) = res else {
+            panic!("wrong password accepted");
+        };
+        assert_eq!(msg, "the SOCKS5 proxy rejected the credentials");
+        let (res, _) = handshake(stub(), "example.com", Auth::None).await;
+        let Err(Error::AuthScheme(msg)) = res else {
+            panic!("no credentials accepted");
+        };
+        assert_eq!(msg, "the SOCKS5 proxy requires credentials");
+    }
+
+    #[compio::test]
+    async fn failure_reply_is_reported() {
+        let stub = Stub {
+            credentials: None,
+            reply: 5,
+        };
+        let (res, _) = handshake(stub, "10.0.0.1", Auth::None).await;
+        let Err(e @ Error::Socks { reply: 5, .. }) = res else {
+            panic!("refused connection succeeded");
+        };
+        assert_eq!(e.exit_code(), 6);
+    }
+}
@@ -0,0 +1,102 @@
+use std::{io, net::SocketAddr};
+
+use compio::{
+    buf::{IoBuf, IoBufMut},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+    BufResult,
+};
+
+use crate::tls::TlsStream;
+
+/// The connection to the proxy.
+///
+/// Like [`TcpStream`], `&ProxyStream` implements both [`AsyncRead`] and
+/// [`AsyncWrite`], so it can be read and written concurrently.
+#[derive(Debug)]
+pub enum ProxyStream {
+    /// A plain TCP connection.
+    Tcp(TcpStream),
+    /// A TLS connection over TCP.
+    Tls(Box<TlsStream>),
+    /// A Unix domain socket.
+    Unix(UnixStream),
+}
+
+impl ProxyStream {
+    /// The address of the other end, which a Unix domain socket doesn't
+    /// have.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(s) => s.peer_addr().ok(),
+            Self::Tls(s) => s.get_ref().peer_addr().ok(),
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// The local address of the connection, which a Unix domain socket
+    /// doesn't have.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(s) => s.local_addr().ok(),
+            Self::Tls(s) => s.get_ref().local_addr().ok(),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for &ProxyStream {
+    async fn read<B: IoBufMut>(&mut self, buf: B) -> BufResult<usize, B> {
+        match self {
+            ProxyStream::Tcp(s) => { s }.read(buf).await,
+            ProxyStream::Tls(s) => { &**s }.read(buf).await,
+            ProxyStream::Unix(s) => { s }.read(buf).await,
+        }
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    async fn read<B: IoBufMut>(&mut self, buf: B) -> BufResult<usize, B> {
+        (&*self).read(buf).await
+    }
+}
+
+impl AsyncWrite for &ProxyStream {
+    async fn write<T: IoBuf>(&mut self, buf: T) -> BufResult<usize, T> {
+        match self {
+            ProxyStream::Tcp(s) => { s }.write(buf).await,
+            ProxyStream::Tls(s) => { &**s }.write(buf).await,
+            ProxyStream::Unix(s) => { s }.write(buf).await,
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ProxyStream::Tcp(s) => { s }.flush().await,
+            ProxyStream::Tls(s) => { &**s }.flush().await,
+            ProxyStream::Unix(s) => { s }.flush().await,
+        }
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            ProxyStream::Tcp(s) => { s }.shutdown().await,
+            ProxyStream::Tls(s) => { &**s }.shutdown().await,
+            ProxyStream::Unix(s) => { s }.shutdown().await,
+        }
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    async fn write<T: IoBuf>(&mut self, buf: T) -> BufResult<usize, T> {
+        (&*self).write(buf).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush().await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        (&*self).shutdown().await
+    }
+}
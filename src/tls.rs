@@ -0,0 +1,373 @@
+use std::{
+    cell::RefCell,
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use compio::{
+    buf::{IoBuf, IoBufMut},
+    fs::File,
+    io::{AsyncRead, AsyncReadAtExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    BufResult,
+};
+use futures_util::lock::Mutex;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{
+        pem::{self, PemObject},
+        CertificateDer, PrivateKeyDer, ServerName, UnixTime,
+    },
+    sign::CertifiedKey,
+    ClientConfig, ClientConnection, DigitallySignedStruct, InconsistentKeys, RootCertStore,
+    SignatureScheme,
+};
+
+use crate::{Error, TunnelConfig};
+
+/// Size of the buffer used to receive TLS records.
+///
+/// It must not exceed the plaintext limit of rustls, so that one read never
+/// produces more plaintext than rustls is willing to hold.
+const RECORD_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Builds the rustls client configuration for the connection to the proxy.
+///
+/// The system trust anchors and the certificates in
+/// [`TunnelConfig::ca_file`] are used to verify the proxy certificate. With
+/// [`TunnelConfig::tls_insecure`], the certificate is not verified at all.
+/// The client certificate, if any, is presented to the proxy.
+pub(crate) async fn client_config(config: &TunnelConfig) -> Result<Arc<ClientConfig>, Error> {
+    let builder = ClientConfig::builder();
+    let provider = builder.crypto_provider().clone();
+    let builder = if config.tls_insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier(provider.clone())))
+    } else {
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        if let Some(path) = &config.ca_file {
+            for cert in read_certs(path).await? {
+                roots.add(cert).map_err(|e| cert_error(path, e))?;
+            }
+        }
+        builder.with_root_certificates(roots)
+    };
+    let client_config = match &config.client_cert {
+        None => builder.with_no_client_auth(),
+        Some(cert_path) => {
+            let certs = read_certs(cert_path).await?;
+            // The key may be in the same file as the certificates.
+            let key_path = config.client_key.as_deref().unwrap_or(cert_path);
+            let key =
+                PrivateKeyDer::from_pem_slice(&read_pem(key_path).await?).map_err(|e| match e {
+                    pem::Error::NoItemsFound => cert_error(key_path, "no private key in PEM file"),
+                    e => cert_error(key_path, e),
+                })?;
+            let signing_key = provider
+                .key_provider
+                .load_private_key(key.clone_key())
+                .map_err(|e| cert_error(key_path, e))?;
+            match CertifiedKey::new(certs.clone(), signing_key).keys_match() {
+                // Not every key can tell its public key.
+                Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => {}
+                Err(rustls::Error::InconsistentKeys(_)) => {
+                    return Err(cert_error(
+                        key_path,
+                        "key does not match the client certificate",
+                    ));
+                }
+                Err(e) => return Err(cert_error(cert_path, e)),
+            }
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| cert_error(key_path, e))?
+        }
+    };
+    Ok(Arc::new(client_config))
+}
+
+/// Reads the certificates in a PEM file, failing if there is none.
+async fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_slice_iter(&read_pem(path).await?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| cert_error(path, e))?;
+    if certs.is_empty() {
+        return Err(cert_error(path, "no certificates in PEM file"));
+    }
+    Ok(certs)
+}
+
+async fn read_pem(path: &Path) -> Result<Vec<u8>, Error> {
+    let io_error = |e: io::Error| {
+        Error::Certificate(io::Error::new(
+            e.kind(),
+            format!("{}: {}", path.display(), e),
+        ))
+    };
+
+    let file = File::open(path).await.map_err(io_error)?;
+    let BufResult(res, buffer) = file.read_to_end_at(vec![], 0).await;
+    res.map_err(io_error)?;
+    Ok(buffer)
+}
+
+fn cert_error(path: &Path, e: impl std::fmt::Display) -> Error {
+    Error::Certificate(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    ))
+}
+
+/// A TLS session to the proxy.
+///
+/// Unlike a single owned TLS stream, `&TlsStream` implements both
+/// [`AsyncRead`] and [`AsyncWrite`], so the two directions of the tunnel can
+/// be driven concurrently, the same way as a `&TcpStream`.
+#[derive(Debug)]
+pub struct TlsStream {
+    conn: RefCell<ClientConnection>,
+    io: TcpStream,
+    write_lock: Mutex<()>,
+}
+
+impl TlsStream {
+    /// Performs the TLS handshake over `io`, sending `server_name` as SNI.
+    pub async fn connect(
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+        io: TcpStream,
+    ) -> io::Result<Self> {
+        let conn = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+        let stream = Self {
+            conn: RefCell::new(conn),
+            io,
+            write_lock: Mutex::new(()),
+        };
+        loop {
+            stream.flush_tls().await?;
+            if !stream.conn.borrow().is_handshaking() {
+                break;
+            }
+            if stream.fill().await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "proxy closed connection during TLS handshake",
+                ));
+            }
+        }
+        Ok(stream)
+    }
+
+    /// The underlying TCP connection.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.io
+    }
+
+    /// Receives TLS records from the underlying stream and processes them.
+    ///
+    /// Returns the number of bytes received, 0 on EOF.
+    async fn fill(&self) -> io::Result<usize> {
+        let mut io = &self.io;
+        let BufResult(res, buffer) = io.read(Vec::with_capacity(RECORD_BUFFER_SIZE)).await;
+        let len = res?;
+        let res = {
+            let mut conn = self.conn.borrow_mut();
+            let mut rest = &buffer[..len];
+            loop {
+                if let Err(e) = conn.read_tls(&mut rest) {
+                    break Err(e);
+                }
+                if let Err(e) = conn.process_new_packets() {
+                    break Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+                if rest.is_empty() {
+                    break Ok(len);
+                }
+            }
+        };
+        // Alerts and post-handshake messages need to be sent even when
+        // processing failed.
+        self.flush_tls().await?;
+        res
+    }
+
+    /// Sends all pending TLS records to the underlying stream.
+    async fn flush_tls(&self) -> io::Result<()> {
+        // Hold the lock while draining, so records from the two directions
+        // are never reordered.
+        let _guard = self.write_lock.lock().await;
+        let mut io = &self.io;
+        loop {
+            let mut buffer = vec![];
+            {
+                let mut conn = self.conn.borrow_mut();
+                while conn.wants_write() {
+                    conn.write_tls(&mut buffer)?;
+                }
+            }
+            if buffer.is_empty() {
+                break;
+            }
+            io.write_all(buffer).await.0?;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for &TlsStream {
+    async fn read<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+        let mut plain = vec![0u8; buf.buf_capacity()];
+        loop {
+            let res = self.conn.borrow_mut().reader().read(&mut plain);
+            match res {
+                Ok(len) => {
+                    for (dst, src) in buf.as_mut_slice().iter_mut().zip(&plain[..len]) {
+                        dst.write(*src);
+                    }
+                    unsafe { buf.set_buf_init(len) };
+                    return BufResult(Ok(len), buf);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if let Err(e) = self.fill().await {
+                        return BufResult(Err(e), buf);
+                    }
+                }
+                // The proxy closed the connection without close_notify.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return BufResult(Ok(0), buf);
+                }
+                Err(e) => return BufResult(Err(e), buf),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for &TlsStream {
+    async fn write<T: IoBuf>(&mut self, buf: T) -> BufResult<usize, T> {
+        let res = self.conn.borrow_mut().writer().write(buf.as_slice());
+        let len = match res {
+            Ok(len) => len,
+            Err(e) => return BufResult(Err(e), buf),
+        };
+        match self.flush_tls().await {
+            Ok(()) => BufResult(Ok(len), buf),
+            Err(e) => BufResult(Err(e), buf),
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.flush_tls().await?;
+        let mut io = &self.io;
+        io.flush().await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.conn.borrow_mut().send_close_notify();
+        self.flush_tls().await?;
+        let mut io = &self.io;
+        io.shutdown().await
+    }
+}
+
+/// Accepts any proxy certificate, only checking the handshake signatures.
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A self-signed CA certificate.
+    const CA_PEM: &str = "\
+        -----BEGIN CERTIFICATE-----\n\
+        MIIBjjCCATWgAwIBAgIUMZ5NieZYPfLmGJXwE7lrVC43ny8wCgYIKoZIzj0EAwIw\n\
+        HDEaMBgGA1UEAwwRcm9ja3NjcmV3IHRlc3QgQ0EwIBcNMjYxMDE0MDYxNTA0WhgP\n\
+        MjEyNjA5MjAwNjE1MDRaMBwxGjAYBgNVBAMMEXJvY2tzY3JldyB0ZXN0IENBMFkw\n\
+        EwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEii7nx9deqWFy5w6Qn04PJNqUelXK18wg\n\
+        QEooT1raIQZsm64slsJtx2z7H6Qih+GG82HCQpvoIWhrUlg7rdaLhqNTMFEwHQYD\n\
+        VR0OBBYEFPsKYXJGPI66Xth6iXdyv19rMw5fMB8GA1UdIwQYMBaAFPsKYXJGPI66\n\
+        Xth6iXdyv19rMw5fMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDRwAwRAIg\n\
+        Bx3+ymllhWNFRzgJ0L/9JX7NyBvI55x4DhePIWVHxbQCIDU0SLXqer4E9Q0iNss2\n\
+        7yZQagH6ZSy+dZZgZ4NwWd4b\n\
+        -----END CERTIFICATE-----\n";
+
+    #[compio::test]
+    async fn ca_file_is_added_to_the_roots() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("rockscrew-{}-ca.pem", std::process::id()));
+        let empty = dir.join(format!("rockscrew-{}-empty.pem", std::process::id()));
+        std::fs::write(&path, CA_PEM).unwrap();
+        std::fs::write(&empty, "not a certificate\n").unwrap();
+        let certs = read_certs(&path).await;
+        let mut config = TunnelConfig::new("proxy", 443, "example.com", 22);
+        config.ca_file = Some(path.clone());
+        let client = client_config(&config).await;
+        let none = read_certs(&empty).await;
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&empty).ok();
+
+        let mut roots = RootCertStore::empty();
+        for cert in certs.unwrap() {
+            roots.add(cert).unwrap();
+        }
+        assert_eq!(roots.len(), 1);
+        assert!(client.is_ok());
+        let Err(Error::Certificate(e)) = none else {
+            panic!("a file without certificates was accepted");
+        };
+        assert!(
+            e.to_string().ends_with("no certificates in PEM file"),
+            "{}",
+            e
+        );
+    }
+}